@@ -0,0 +1,398 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::agg::{hash_builder, Agg, AggAccum, AggAccumRef};
+use ahash::RandomState;
+use arrow::array::*;
+use arrow::datatypes::*;
+use datafusion::common::{Result, ScalarValue};
+use datafusion::physical_expr::PhysicalExpr;
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// `collect_list`: gathers every non-null input value of a group into a
+/// single `List<child>` row, preserving input order within a partition.
+pub struct AggCollectList {
+    child: Arc<dyn PhysicalExpr>,
+    child_data_type: DataType,
+    data_type: DataType,
+    accum_fields: Vec<Field>,
+}
+
+impl AggCollectList {
+    pub fn try_new(child: Arc<dyn PhysicalExpr>, child_data_type: DataType) -> Result<Self> {
+        let data_type = DataType::List(Arc::new(Field::new("item", child_data_type.clone(), true)));
+        let accum_fields = vec![Field::new("values", data_type.clone(), true)];
+        Ok(Self {
+            child,
+            child_data_type,
+            data_type,
+            accum_fields,
+        })
+    }
+}
+
+impl Debug for AggCollectList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CollectList({:?})", self.child)
+    }
+}
+
+impl Agg for AggCollectList {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.child.clone()]
+    }
+
+    fn data_type(&self) -> &DataType {
+        &self.data_type
+    }
+
+    fn nullable(&self) -> bool {
+        false
+    }
+
+    fn accum_fields(&self) -> &[Field] {
+        &self.accum_fields
+    }
+
+    fn create_accum(&self) -> Result<AggAccumRef> {
+        Ok(Box::new(CollectListAccum {
+            values: vec![],
+            child_data_type: self.child_data_type.clone(),
+        }))
+    }
+}
+
+pub struct CollectListAccum {
+    pub values: Vec<ScalarValue>,
+    child_data_type: DataType,
+}
+
+impl AggAccum for CollectListAccum {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+
+    fn mem_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.values.iter().map(|v| v.size()).sum::<usize>()
+    }
+
+    fn load(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        self.values = read_list_element(&values[0], row_idx, &self.child_data_type)?;
+        Ok(())
+    }
+
+    fn save(&self, builders: &mut [Box<dyn ArrayBuilder>]) -> Result<()> {
+        write_list_row(&mut builders[0], &self.values)
+    }
+
+    fn save_final(&self, builder: &mut Box<dyn ArrayBuilder>) -> Result<()> {
+        write_list_row(builder, &self.values)
+    }
+
+    fn partial_update(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        if values[0].is_valid(row_idx) {
+            self.values.push(ScalarValue::try_from_array(&values[0], row_idx)?);
+        }
+        Ok(())
+    }
+
+    fn partial_update_all(&mut self, values: &[ArrayRef]) -> Result<()> {
+        for row_idx in 0..values[0].len() {
+            self.partial_update(values, row_idx)?;
+        }
+        Ok(())
+    }
+
+    fn partial_merge(&mut self, another: AggAccumRef) -> Result<()> {
+        let another_collect = another.into_any().downcast::<CollectListAccum>().unwrap();
+        self.values.extend(another_collect.values);
+        Ok(())
+    }
+
+    fn partial_merge_from_array(
+        &mut self,
+        partial_agg_values: &[ArrayRef],
+        row_idx: usize,
+    ) -> Result<()> {
+        self.values
+            .extend(read_list_element(&partial_agg_values[0], row_idx, &self.child_data_type)?);
+        Ok(())
+    }
+}
+
+/// `collect_set`: like `collect_list` but deduplicates values with an
+/// aHash-backed set, so dedup stays O(1) per element and is consistent
+/// across partitions when partial sets are merged.
+pub struct AggCollectSet {
+    child: Arc<dyn PhysicalExpr>,
+    child_data_type: DataType,
+    data_type: DataType,
+    accum_fields: Vec<Field>,
+}
+
+impl AggCollectSet {
+    pub fn try_new(child: Arc<dyn PhysicalExpr>, child_data_type: DataType) -> Result<Self> {
+        let data_type = DataType::List(Arc::new(Field::new("item", child_data_type.clone(), true)));
+        let accum_fields = vec![Field::new("values", data_type.clone(), true)];
+        Ok(Self {
+            child,
+            child_data_type,
+            data_type,
+            accum_fields,
+        })
+    }
+}
+
+impl Debug for AggCollectSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CollectSet({:?})", self.child)
+    }
+}
+
+impl Agg for AggCollectSet {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.child.clone()]
+    }
+
+    fn data_type(&self) -> &DataType {
+        &self.data_type
+    }
+
+    fn nullable(&self) -> bool {
+        false
+    }
+
+    fn accum_fields(&self) -> &[Field] {
+        &self.accum_fields
+    }
+
+    fn create_accum(&self) -> Result<AggAccumRef> {
+        Ok(Box::new(CollectSetAccum {
+            values: HashSet::with_hasher(hash_builder()),
+            child_data_type: self.child_data_type.clone(),
+        }))
+    }
+}
+
+pub struct CollectSetAccum {
+    pub values: HashSet<ScalarValue, RandomState>,
+    child_data_type: DataType,
+}
+
+impl AggAccum for CollectSetAccum {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+
+    fn mem_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.values.iter().map(|v| v.size()).sum::<usize>()
+    }
+
+    fn load(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        self.values = read_list_element(&values[0], row_idx, &self.child_data_type)?
+            .into_iter()
+            .collect();
+        Ok(())
+    }
+
+    fn save(&self, builders: &mut [Box<dyn ArrayBuilder>]) -> Result<()> {
+        let values: Vec<ScalarValue> = self.values.iter().cloned().collect();
+        write_list_row(&mut builders[0], &values)
+    }
+
+    fn save_final(&self, builder: &mut Box<dyn ArrayBuilder>) -> Result<()> {
+        let values: Vec<ScalarValue> = self.values.iter().cloned().collect();
+        write_list_row(builder, &values)
+    }
+
+    fn partial_update(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        if values[0].is_valid(row_idx) {
+            self.values
+                .insert(ScalarValue::try_from_array(&values[0], row_idx)?);
+        }
+        Ok(())
+    }
+
+    fn partial_update_all(&mut self, values: &[ArrayRef]) -> Result<()> {
+        for row_idx in 0..values[0].len() {
+            self.partial_update(values, row_idx)?;
+        }
+        Ok(())
+    }
+
+    fn partial_merge(&mut self, another: AggAccumRef) -> Result<()> {
+        let another_collect = another.into_any().downcast::<CollectSetAccum>().unwrap();
+        self.values.extend(another_collect.values);
+        Ok(())
+    }
+
+    fn partial_merge_from_array(
+        &mut self,
+        partial_agg_values: &[ArrayRef],
+        row_idx: usize,
+    ) -> Result<()> {
+        self.values.extend(read_list_element(
+            &partial_agg_values[0],
+            row_idx,
+            &self.child_data_type,
+        )?);
+        Ok(())
+    }
+}
+
+/// Reads one `List<child>` row back into its constituent scalars.
+fn read_list_element(
+    array: &ArrayRef,
+    row_idx: usize,
+    _child_data_type: &DataType,
+) -> Result<Vec<ScalarValue>> {
+    let list = array.as_any().downcast_ref::<ListArray>().unwrap();
+    if list.is_null(row_idx) {
+        return Ok(vec![]);
+    }
+    let child = list.value(row_idx);
+    (0..child.len())
+        .map(|i| ScalarValue::try_from_array(&child, i))
+        .collect()
+}
+
+/// Appends one `List<child>` row built from `values` to a `ListBuilder`.
+fn write_list_row(builder: &mut Box<dyn ArrayBuilder>, values: &[ScalarValue]) -> Result<()> {
+    let list_builder = builder
+        .as_any_mut()
+        .downcast_mut::<ListBuilder<Box<dyn ArrayBuilder>>>()
+        .unwrap();
+    for value in values {
+        crate::agg::save_scalar(value, list_builder.values())?;
+    }
+    list_builder.append(true);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_i64_builder() -> Box<dyn ArrayBuilder> {
+        arrow::array::make_builder(&DataType::List(Arc::new(Field::new("item", DataType::Int64, true))), 1)
+    }
+
+    #[test]
+    fn test_collect_list_preserves_order_and_skips_nulls() {
+        let mut accum = CollectListAccum {
+            values: vec![],
+            child_data_type: DataType::Int64,
+        };
+        let values: Vec<ArrayRef> = vec![Arc::new(Int64Array::from(vec![Some(3), None, Some(1), Some(3)]))];
+        accum.partial_update_all(&values).unwrap();
+
+        assert_eq!(
+            accum.values,
+            vec![
+                ScalarValue::Int64(Some(3)),
+                ScalarValue::Int64(Some(1)),
+                ScalarValue::Int64(Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_set_dedups_and_merge_matches_single_pass() {
+        let values: Vec<ArrayRef> = vec![Arc::new(Int64Array::from(vec![1, 2, 2, 3]))];
+        let other_values: Vec<ArrayRef> = vec![Arc::new(Int64Array::from(vec![3, 4]))];
+
+        let mut whole = CollectSetAccum {
+            values: HashSet::with_hasher(hash_builder()),
+            child_data_type: DataType::Int64,
+        };
+        whole.partial_update_all(&values).unwrap();
+        whole.partial_update_all(&other_values).unwrap();
+
+        let mut first = CollectSetAccum {
+            values: HashSet::with_hasher(hash_builder()),
+            child_data_type: DataType::Int64,
+        };
+        first.partial_update_all(&values).unwrap();
+        let mut second = CollectSetAccum {
+            values: HashSet::with_hasher(hash_builder()),
+            child_data_type: DataType::Int64,
+        };
+        second.partial_update_all(&other_values).unwrap();
+        first.partial_merge(Box::new(second)).unwrap();
+
+        let mut whole_sorted: Vec<i64> = whole
+            .values
+            .iter()
+            .map(|v| match v {
+                ScalarValue::Int64(Some(i)) => *i,
+                _ => unreachable!(),
+            })
+            .collect();
+        let mut merged_sorted: Vec<i64> = first
+            .values
+            .iter()
+            .map(|v| match v {
+                ScalarValue::Int64(Some(i)) => *i,
+                _ => unreachable!(),
+            })
+            .collect();
+        whole_sorted.sort();
+        merged_sorted.sort();
+        assert_eq!(merged_sorted, vec![1, 2, 3, 4]);
+        assert_eq!(merged_sorted, whole_sorted);
+    }
+
+    #[test]
+    fn test_collect_list_save_load_roundtrip() {
+        let mut accum = CollectListAccum {
+            values: vec![
+                ScalarValue::Int64(Some(1)),
+                ScalarValue::Int64(Some(2)),
+                ScalarValue::Int64(Some(3)),
+            ],
+            child_data_type: DataType::Int64,
+        };
+
+        let mut builders = vec![list_i64_builder()];
+        accum.save(&mut builders).unwrap();
+        let array = builders[0].finish();
+
+        let mut reloaded = CollectListAccum {
+            values: vec![],
+            child_data_type: DataType::Int64,
+        };
+        reloaded.load(&[Arc::new(array)], 0).unwrap();
+
+        assert_eq!(reloaded.values, accum.values);
+    }
+}
@@ -110,7 +110,7 @@ impl AggAccum for AggMaxAccum {
                 let value = downcast_value!(values[0], TArray);
                 if value.is_valid(row_idx) {
                     let new = value.value(row_idx);
-                    if $partial_value.is_none() || new < $partial_value.unwrap() {
+                    if $partial_value.is_none() || new > $partial_value.unwrap() {
                         *$partial_value = Some(new);
                     }
                 }
@@ -134,7 +134,7 @@ impl AggAccum for AggMaxAccum {
                 let value = downcast_value!(values[0], Decimal128Array);
                 if value.is_valid(row_idx) {
                     let new = value.value(row_idx);
-                    if v.is_none() || new < v.unwrap() {
+                    if v.is_none() || new > v.unwrap() {
                         *v = Some(new);
                     }
                 }
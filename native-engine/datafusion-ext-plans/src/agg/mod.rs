@@ -0,0 +1,154 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod approx_count_distinct;
+pub mod collect;
+pub mod max;
+pub mod percentile;
+pub mod variance;
+
+use std::{any::Any, fmt::Debug, sync::Arc};
+
+use ahash::RandomState;
+use arrow::array::*;
+use arrow::datatypes::*;
+use datafusion::common::{Result, ScalarValue};
+use datafusion::physical_expr::PhysicalExpr;
+use paste::paste;
+
+/// Fixed seeds so hashing the same bytes (a group key, a `collect_set`
+/// element, ...) is deterministic across executor partitions -- required
+/// wherever a hash computed on one partition must agree with a hash
+/// computed on another, e.g. the shuffle exchange or a partial-state
+/// merge.
+const HASH_SEEDS: (u64, u64, u64, u64) = (
+    0x9E37_79B9_7F4A_7C15,
+    0xBF58_476D_1CE4_E5B9,
+    0x94D0_49BB_1331_11EB,
+    0x2545_F491_4F6C_DD1D,
+);
+
+/// A fixed-seed aHash `RandomState`, shared by every aggregate/operator in
+/// this crate that needs partition-stable hashing.
+pub fn hash_builder() -> RandomState {
+    RandomState::with_seeds(HASH_SEEDS.0, HASH_SEEDS.1, HASH_SEEDS.2, HASH_SEEDS.3)
+}
+
+/// A single aggregate expression, e.g. `max(x)` or `sum(y)`.
+///
+/// An `Agg` describes the shape of an aggregation (its input expressions,
+/// output type and the fields used to spill/exchange partial state) and acts
+/// as a factory for per-group [`AggAccum`]s.
+pub trait Agg: Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>>;
+    fn data_type(&self) -> &DataType;
+    fn nullable(&self) -> bool;
+
+    /// Fields used to store/round-trip this aggregate's partial state,
+    /// e.g. through `save`/`load` during shuffle or spilling.
+    fn accum_fields(&self) -> &[Field];
+
+    /// Creates a fresh accumulator for one group.
+    fn create_accum(&self) -> Result<AggAccumRef>;
+}
+
+pub type AggAccumRef = Box<dyn AggAccum>;
+
+/// Per-group accumulator state for an [`Agg`].
+pub trait AggAccum: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+
+    /// Approximate heap usage of this accumulator, used to trigger spilling.
+    fn mem_size(&self) -> usize;
+
+    /// Loads partial state previously written by `save`.
+    fn load(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()>;
+
+    /// Writes partial state so it can be merged later.
+    fn save(&self, builders: &mut [Box<dyn ArrayBuilder>]) -> Result<()>;
+
+    /// Writes the final aggregate result.
+    fn save_final(&self, builder: &mut Box<dyn ArrayBuilder>) -> Result<()>;
+
+    /// Updates this accumulator with a single input row.
+    fn partial_update(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()>;
+
+    /// Updates this accumulator with a whole batch of input rows at once.
+    fn partial_update_all(&mut self, values: &[ArrayRef]) -> Result<()>;
+
+    /// Merges another accumulator's state into this one.
+    fn partial_merge(&mut self, another: AggAccumRef) -> Result<()>;
+
+    /// Merges partial state read directly from an array (e.g. during a
+    /// shuffle merge phase where no live accumulator exists yet).
+    fn partial_merge_from_array(
+        &mut self,
+        partial_agg_values: &[ArrayRef],
+        row_idx: usize,
+    ) -> Result<()>;
+}
+
+/// Loads a single [`ScalarValue`] from `array[row_idx]`, replacing `scalar`
+/// with a value of the array's data type.
+pub fn load_scalar(scalar: &mut ScalarValue, array: &ArrayRef, row_idx: usize) -> Result<()> {
+    *scalar = ScalarValue::try_from_array(array, row_idx)?;
+    Ok(())
+}
+
+/// Appends a single [`ScalarValue`] to a builder of the matching type.
+pub fn save_scalar(scalar: &ScalarValue, builder: &mut Box<dyn ArrayBuilder>) -> Result<()> {
+    macro_rules! handle {
+        ($tyname:ident, $v:expr) => {{
+            type TBuilder = paste! {[<$tyname Builder>]};
+            builder
+                .as_any_mut()
+                .downcast_mut::<TBuilder>()
+                .unwrap()
+                .append_option(*$v);
+        }};
+    }
+
+    match scalar {
+        ScalarValue::Null => builder.append_null(),
+        ScalarValue::Boolean(v) => handle!(Boolean, v),
+        ScalarValue::Float32(v) => handle!(Float32, v),
+        ScalarValue::Float64(v) => handle!(Float64, v),
+        ScalarValue::Int8(v) => handle!(Int8, v),
+        ScalarValue::Int16(v) => handle!(Int16, v),
+        ScalarValue::Int32(v) => handle!(Int32, v),
+        ScalarValue::Int64(v) => handle!(Int64, v),
+        ScalarValue::UInt8(v) => handle!(UInt8, v),
+        ScalarValue::UInt16(v) => handle!(UInt16, v),
+        ScalarValue::UInt32(v) => handle!(UInt32, v),
+        ScalarValue::UInt64(v) => handle!(UInt64, v),
+        ScalarValue::Utf8(v) => {
+            let b = builder.as_any_mut().downcast_mut::<StringBuilder>().unwrap();
+            match v {
+                Some(s) => b.append_value(s),
+                None => b.append_null(),
+            }
+        }
+        ScalarValue::Date32(v) => handle!(Date32, v),
+        ScalarValue::Date64(v) => handle!(Date64, v),
+        other => {
+            return Err(datafusion::error::DataFusionError::NotImplemented(format!(
+                "save_scalar: unsupported scalar type: {}",
+                other
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,511 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::agg::{Agg, AggAccum, AggAccumRef};
+use arrow::array::*;
+use arrow::datatypes::*;
+use datafusion::common::{downcast_value, Result};
+use datafusion::physical_expr::PhysicalExpr;
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Default compression factor: higher values keep more, smaller centroids
+/// and therefore give a more accurate digest at the cost of more state,
+/// matching Spark's default for `percentile_approx`.
+const DEFAULT_COMPRESSION: f64 = 100.0;
+
+/// Spark's `percentile_approx`, backed by a mergeable t-digest so partial
+/// digests computed on different partitions can be combined cheaply
+/// without re-sorting the whole input.
+pub struct AggApproxPercentile {
+    child: Arc<dyn PhysicalExpr>,
+    percentiles: Vec<f64>,
+    compression: f64,
+    data_type: DataType,
+    accum_fields: Vec<Field>,
+}
+
+impl AggApproxPercentile {
+    pub fn try_new(child: Arc<dyn PhysicalExpr>, percentiles: Vec<f64>) -> Result<Self> {
+        Self::try_new_with_compression(child, percentiles, DEFAULT_COMPRESSION)
+    }
+
+    pub fn try_new_with_compression(
+        child: Arc<dyn PhysicalExpr>,
+        percentiles: Vec<f64>,
+        compression: f64,
+    ) -> Result<Self> {
+        // a single percentile returns a scalar, matching Spark; an array
+        // argument returns an array of the same length.
+        let data_type = if percentiles.len() == 1 {
+            DataType::Float64
+        } else {
+            // nullable: save_final() emits a null element for a group with
+            // zero observed values (empty digest -> no quantile to report).
+            DataType::List(Arc::new(Field::new("item", DataType::Float64, true)))
+        };
+        let accum_fields = vec![
+            Field::new(
+                "centroid_means",
+                DataType::List(Arc::new(Field::new("item", DataType::Float64, false))),
+                false,
+            ),
+            Field::new(
+                "centroid_weights",
+                DataType::List(Arc::new(Field::new("item", DataType::Float64, false))),
+                false,
+            ),
+        ];
+        Ok(Self {
+            child,
+            percentiles,
+            compression,
+            data_type,
+            accum_fields,
+        })
+    }
+}
+
+impl Debug for AggApproxPercentile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ApproxPercentile({:?}, {:?})", self.child, self.percentiles)
+    }
+}
+
+impl Agg for AggApproxPercentile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.child.clone()]
+    }
+
+    fn data_type(&self) -> &DataType {
+        &self.data_type
+    }
+
+    fn nullable(&self) -> bool {
+        true
+    }
+
+    fn accum_fields(&self) -> &[Field] {
+        &self.accum_fields
+    }
+
+    fn create_accum(&self) -> Result<AggAccumRef> {
+        Ok(Box::new(TDigestAccum {
+            centroids: vec![],
+            buffer: vec![],
+            total_weight: 0.0,
+            percentiles: self.percentiles.clone(),
+            compression: self.compression,
+        }))
+    }
+}
+
+/// One t-digest centroid: a `mean` with accumulated `weight`.
+#[derive(Clone, Copy, Debug)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// How many uncompressed values to buffer before merge-compressing, to
+/// amortize the O(n log n) sort over many `partial_update` calls.
+const COMPRESS_BUFFER_SIZE: usize = 1000;
+
+pub struct TDigestAccum {
+    centroids: Vec<Centroid>,
+    /// Values observed since the last compression, not yet folded into
+    /// `centroids`.
+    buffer: Vec<f64>,
+    total_weight: f64,
+    percentiles: Vec<f64>,
+    compression: f64,
+}
+
+impl TDigestAccum {
+    fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        if self.buffer.len() >= COMPRESS_BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    /// Merges the pending buffer into `centroids` and re-runs compression,
+    /// bounding each centroid's weight by `q*(1-q)*4*n/compression` where
+    /// `q` is its cumulative-weight midpoint quantile.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() && self.centroids.len() <= 1 {
+            return;
+        }
+        let mut all: Vec<Centroid> = self
+            .centroids
+            .drain(..)
+            .chain(self.buffer.drain(..).map(|v| Centroid { mean: v, weight: 1.0 }))
+            .collect();
+        if all.is_empty() {
+            return;
+        }
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let n: f64 = all.iter().map(|c| c.weight).sum();
+        self.total_weight = n;
+
+        let mut merged: Vec<Centroid> = Vec::with_capacity(all.len());
+        let mut cumulative = 0.0;
+        for c in all {
+            if let Some(last) = merged.last_mut() {
+                let merged_weight = last.weight + c.weight;
+                // `cumulative` already includes `last.weight`, so back it
+                // out before adding the midpoint of the would-be merge.
+                let q = (cumulative - last.weight + merged_weight / 2.0) / n;
+                let max_weight = 4.0 * n * q * (1.0 - q) / self.compression;
+                if merged_weight <= max_weight {
+                    last.mean = (last.mean * last.weight + c.mean * c.weight) / merged_weight;
+                    last.weight = merged_weight;
+                    cumulative += c.weight;
+                    continue;
+                }
+            }
+            cumulative += c.weight;
+            merged.push(c);
+        }
+        self.centroids = merged;
+    }
+
+    /// Interpolates the quantile `q` between the two centroids whose
+    /// cumulative weight spans `q * n`, clamping to the extreme centroid
+    /// at either tail.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.centroids.is_empty() {
+            return None;
+        }
+        if self.centroids.len() == 1 {
+            return Some(self.centroids[0].mean);
+        }
+
+        let n = self.total_weight;
+        let target = q * n;
+        let mut cumulative = 0.0;
+
+        for i in 0..self.centroids.len() {
+            let c = self.centroids[i];
+            let next_cumulative = cumulative + c.weight;
+            if target <= next_cumulative || i == self.centroids.len() - 1 {
+                if i == 0 {
+                    return Some(c.mean);
+                }
+                if i == self.centroids.len() - 1 && target > next_cumulative {
+                    return Some(c.mean);
+                }
+                let prev = self.centroids[i - 1];
+
+                // midpoints of the two centroids bracketing `target`
+                let mid_prev = cumulative - prev.weight / 2.0;
+                let mid_cur = cumulative + c.weight / 2.0;
+                if mid_cur == mid_prev {
+                    return Some(c.mean);
+                }
+                let frac = (target - mid_prev) / (mid_cur - mid_prev);
+                let frac = frac.clamp(0.0, 1.0);
+                return Some(prev.mean + frac * (c.mean - prev.mean));
+            }
+            cumulative = next_cumulative;
+        }
+        self.centroids.last().map(|c| c.mean)
+    }
+}
+
+impl AggAccum for TDigestAccum {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+
+    fn mem_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.centroids.len() * std::mem::size_of::<Centroid>()
+            + self.buffer.len() * std::mem::size_of::<f64>()
+    }
+
+    fn load(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        self.compress();
+        let means = downcast_value!(values[0], ListArray).value(row_idx);
+        let weights = downcast_value!(values[1], ListArray).value(row_idx);
+        let means = downcast_value!(means, Float64Array);
+        let weights = downcast_value!(weights, Float64Array);
+        self.centroids = (0..means.len())
+            .map(|i| Centroid {
+                mean: means.value(i),
+                weight: weights.value(i),
+            })
+            .collect();
+        self.total_weight = self.centroids.iter().map(|c| c.weight).sum();
+        Ok(())
+    }
+
+    fn save(&self, builders: &mut [Box<dyn ArrayBuilder>]) -> Result<()> {
+        // merge-compress before spilling so the serialized digest is
+        // bounded rather than growing with every `partial_update`.
+        let mut this = TDigestAccum {
+            centroids: self.centroids.clone(),
+            buffer: self.buffer.clone(),
+            total_weight: self.total_weight,
+            percentiles: self.percentiles.clone(),
+            compression: self.compression,
+        };
+        this.compress();
+
+        write_f64_list(&mut builders[0], this.centroids.iter().map(|c| Some(c.mean)))?;
+        write_f64_list(&mut builders[1], this.centroids.iter().map(|c| Some(c.weight)))?;
+        Ok(())
+    }
+
+    fn save_final(&self, builder: &mut Box<dyn ArrayBuilder>) -> Result<()> {
+        let mut this = TDigestAccum {
+            centroids: self.centroids.clone(),
+            buffer: self.buffer.clone(),
+            total_weight: self.total_weight,
+            percentiles: self.percentiles.clone(),
+            compression: self.compression,
+        };
+        this.compress();
+
+        if self.percentiles.len() == 1 {
+            builder
+                .as_any_mut()
+                .downcast_mut::<Float64Builder>()
+                .unwrap()
+                .append_option(this.quantile(self.percentiles[0]));
+        } else {
+            write_f64_list(builder, self.percentiles.iter().map(|&q| this.quantile(q)))?;
+        }
+        Ok(())
+    }
+
+    fn partial_update(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        let value = downcast_value!(values[0], Float64Array);
+        if value.is_valid(row_idx) {
+            self.add(value.value(row_idx));
+        }
+        Ok(())
+    }
+
+    fn partial_update_all(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let value = downcast_value!(values[0], Float64Array);
+        for i in 0..value.len() {
+            if value.is_valid(i) {
+                self.add(value.value(i));
+            }
+        }
+        Ok(())
+    }
+
+    fn partial_merge(&mut self, another: AggAccumRef) -> Result<()> {
+        let mut another_digest = another.into_any().downcast::<TDigestAccum>().unwrap();
+        another_digest.compress();
+        self.compress();
+        self.centroids.extend(another_digest.centroids);
+        self.buffer.extend(another_digest.buffer);
+        self.compress();
+        Ok(())
+    }
+
+    fn partial_merge_from_array(
+        &mut self,
+        partial_agg_values: &[ArrayRef],
+        row_idx: usize,
+    ) -> Result<()> {
+        let means = downcast_value!(partial_agg_values[0], ListArray).value(row_idx);
+        let weights = downcast_value!(partial_agg_values[1], ListArray).value(row_idx);
+        let means = downcast_value!(means, Float64Array);
+        let weights = downcast_value!(weights, Float64Array);
+        self.compress();
+        self.centroids
+            .extend((0..means.len()).map(|i| Centroid {
+                mean: means.value(i),
+                weight: weights.value(i),
+            }));
+        self.compress();
+        Ok(())
+    }
+}
+
+/// Appends one `List<Float64>` row to a builder.
+///
+/// `builders` here are always produced by `arrow::array::make_builder` on
+/// this digest's `List<Float64>` accum/output fields (see
+/// `HashAggMap::spill`/`finish` in `agg_exec.rs`), which for a list type
+/// returns a `ListBuilder<Box<dyn ArrayBuilder>>`, not a
+/// `ListBuilder<Float64Builder>` -- the same distinction `collect.rs`'s
+/// `write_list_row` already accounts for.
+fn write_f64_list(
+    builder: &mut Box<dyn ArrayBuilder>,
+    values: impl Iterator<Item = Option<f64>>,
+) -> Result<()> {
+    let list_builder = builder
+        .as_any_mut()
+        .downcast_mut::<ListBuilder<Box<dyn ArrayBuilder>>>()
+        .unwrap();
+    let value_builder = list_builder
+        .values()
+        .as_any_mut()
+        .downcast_mut::<Float64Builder>()
+        .unwrap();
+    for v in values {
+        value_builder.append_option(v);
+    }
+    list_builder.append(true);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_accum(percentiles: Vec<f64>) -> TDigestAccum {
+        TDigestAccum {
+            centroids: vec![],
+            buffer: vec![],
+            total_weight: 0.0,
+            percentiles,
+            compression: DEFAULT_COMPRESSION,
+        }
+    }
+
+    #[test]
+    fn test_quantile_on_uniform_distribution() {
+        let mut accum = new_accum(vec![0.5]);
+        for i in 1..=1000 {
+            accum.add(i as f64);
+        }
+        accum.compress();
+        let median = accum.quantile(0.5).unwrap();
+        assert!((median - 500.5).abs() < 10.0, "median was {median}");
+    }
+
+    #[test]
+    fn test_merge_matches_single_digest() {
+        let mut whole = new_accum(vec![0.5]);
+        let mut first_half = new_accum(vec![0.5]);
+        let mut second_half = new_accum(vec![0.5]);
+        for i in 1..=1000 {
+            whole.add(i as f64);
+            if i <= 500 {
+                first_half.add(i as f64);
+            } else {
+                second_half.add(i as f64);
+            }
+        }
+        whole.compress();
+        first_half.compress();
+        second_half.compress();
+
+        first_half.centroids.extend(second_half.centroids);
+        first_half.buffer.extend(second_half.buffer);
+        first_half.compress();
+
+        let merged_median = first_half.quantile(0.5).unwrap();
+        let whole_median = whole.quantile(0.5).unwrap();
+        assert!(
+            (merged_median - whole_median).abs() < 20.0,
+            "merged={merged_median} whole={whole_median}"
+        );
+    }
+
+    #[test]
+    fn test_save_load_roundtrip_preserves_total_weight() {
+        let mut accum = new_accum(vec![0.5]);
+        for i in 1..=200 {
+            accum.add(i as f64);
+        }
+        accum.compress();
+
+        // go through the same `arrow::array::make_builder` path
+        // `agg_exec.rs`'s `HashAggMap::spill`/`finish` use in production,
+        // not a hand-built `ListBuilder<Float64Builder>`: for a
+        // `List<Float64>` field `make_builder` returns a
+        // `ListBuilder<Box<dyn ArrayBuilder>>`, and `save`/`save_final`
+        // must downcast to that, not the narrower type.
+        let centroid_field = DataType::List(Arc::new(Field::new("item", DataType::Float64, false)));
+        let mut builders: Vec<Box<dyn ArrayBuilder>> = vec![
+            arrow::array::make_builder(&centroid_field, 1),
+            arrow::array::make_builder(&centroid_field, 1),
+        ];
+        accum.save(&mut builders).unwrap();
+        let means_array = builders[0].finish();
+        let weights_array = builders[1].finish();
+
+        let mut reloaded = new_accum(vec![0.5]);
+        reloaded
+            .load(&[Arc::new(means_array), Arc::new(weights_array)], 0)
+            .unwrap();
+
+        assert_eq!(reloaded.total_weight, accum.total_weight);
+        assert_eq!(reloaded.centroids.len(), accum.centroids.len());
+    }
+
+    #[test]
+    fn test_empty_accum_has_no_quantile() {
+        let accum = new_accum(vec![0.5]);
+        assert_eq!(accum.quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_nan_input_does_not_panic_compress_or_save_final() {
+        // NaN is a valid, non-null f64 and isn't filtered by the
+        // `is_valid` check in `partial_update`, so `compress()`'s sort
+        // must not panic when one slips into the buffer.
+        let mut accum = new_accum(vec![0.5]);
+        let values: Vec<ArrayRef> = vec![Arc::new(Float64Array::from(vec![1.0, f64::NAN, 2.0, 3.0]))];
+        for row_idx in 0..values[0].len() {
+            accum.partial_update(&values, row_idx).unwrap();
+        }
+        accum.compress();
+
+        let mut builder: Box<dyn ArrayBuilder> = Box::new(Float64Builder::new());
+        accum.save_final(&mut builder).unwrap();
+        builder.finish();
+    }
+
+    #[test]
+    fn test_save_final_multi_percentile_through_real_builder() {
+        let mut accum = new_accum(vec![0.25, 0.5, 0.75]);
+        for i in 1..=1000 {
+            accum.add(i as f64);
+        }
+
+        // same `List<Float64>` shape `AggApproxPercentile::data_type()`
+        // reports for a multi-percentile call, built the way
+        // `agg_exec.rs`'s `finish()` builds it.
+        let output_field = DataType::List(Arc::new(Field::new("item", DataType::Float64, true)));
+        let mut builder = arrow::array::make_builder(&output_field, 1);
+        accum.save_final(&mut builder).unwrap();
+        let array = builder.finish();
+
+        let list = array.as_any().downcast_ref::<ListArray>().unwrap();
+        let row = list.value(0);
+        let row = row.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(row.len(), 3);
+        assert!((row.value(0) - 250.0).abs() < 10.0);
+        assert!((row.value(1) - 500.0).abs() < 10.0);
+        assert!((row.value(2) - 750.0).abs() < 10.0);
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::agg::{Agg, AggAccum, AggAccumRef};
+use arrow::array::*;
+use arrow::datatypes::*;
+use datafusion::common::{downcast_value, Result};
+use datafusion::error::DataFusionError;
+use datafusion::physical_expr::PhysicalExpr;
+use paste::paste;
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Reads `array[row_idx]` as `f64`, dispatching over every numeric type
+/// Spark may feed `var_samp`/`stddev_samp` etc. with, the same way
+/// `max.rs` dispatches over `ScalarValue` variants for `max()`.
+fn value_as_f64(array: &ArrayRef, row_idx: usize) -> Result<Option<f64>> {
+    macro_rules! handle {
+        ($tyname:ident) => {{
+            type TArray = paste! {[<$tyname Array>]};
+            let value = downcast_value!(array, TArray);
+            Ok(value.is_valid(row_idx).then(|| value.value(row_idx) as f64))
+        }};
+    }
+
+    match array.data_type() {
+        DataType::Float32 => handle!(Float32),
+        DataType::Float64 => handle!(Float64),
+        DataType::Int8 => handle!(Int8),
+        DataType::Int16 => handle!(Int16),
+        DataType::Int32 => handle!(Int32),
+        DataType::Int64 => handle!(Int64),
+        DataType::UInt8 => handle!(UInt8),
+        DataType::UInt16 => handle!(UInt16),
+        DataType::UInt32 => handle!(UInt32),
+        DataType::UInt64 => handle!(UInt64),
+        DataType::Decimal128(_, scale) => {
+            let value = downcast_value!(array, Decimal128Array);
+            Ok(value
+                .is_valid(row_idx)
+                .then(|| value.value(row_idx) as f64 / 10f64.powi(*scale as i32)))
+        }
+        other => Err(DataFusionError::NotImplemented(format!(
+            "unsupported data type in variance/stddev: {}",
+            other
+        ))),
+    }
+}
+
+/// Whether a moment aggregate computes the sample (`n - 1` denominator) or
+/// population (`n` denominator) statistic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsType {
+    Sample,
+    Population,
+}
+
+/// `var_samp`/`var_pop`. Accumulates the running count/mean/M2 moments
+/// using Welford's online algorithm and combines partial states with
+/// Chan's parallel variance formula, so the result is stable under Spark's
+/// partial -> merge -> final aggregation flow.
+pub struct AggVariance {
+    child: Arc<dyn PhysicalExpr>,
+    accum_fields: Vec<Field>,
+    stats_type: StatsType,
+}
+
+impl AggVariance {
+    pub fn try_new(child: Arc<dyn PhysicalExpr>, stats_type: StatsType) -> Result<Self> {
+        let accum_fields = vec![
+            Field::new("count", DataType::UInt64, false),
+            Field::new("mean", DataType::Float64, false),
+            Field::new("m2", DataType::Float64, false),
+        ];
+        Ok(Self {
+            child,
+            accum_fields,
+            stats_type,
+        })
+    }
+}
+
+impl Debug for AggVariance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.stats_type {
+            StatsType::Sample => write!(f, "VarSamp({:?})", self.child),
+            StatsType::Population => write!(f, "VarPop({:?})", self.child),
+        }
+    }
+}
+
+impl Agg for AggVariance {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.child.clone()]
+    }
+
+    fn data_type(&self) -> &DataType {
+        &DataType::Float64
+    }
+
+    fn nullable(&self) -> bool {
+        true
+    }
+
+    fn accum_fields(&self) -> &[Field] {
+        &self.accum_fields
+    }
+
+    fn create_accum(&self) -> Result<AggAccumRef> {
+        Ok(Box::new(MomentsAccum {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            stats_type: self.stats_type,
+            output_stddev: false,
+        }))
+    }
+}
+
+/// `stddev_samp`/`stddev_pop`, sharing the same Welford/Chan moments
+/// accumulator as [`AggVariance`] and taking the square root in
+/// `save_final`.
+pub struct AggStddev {
+    child: Arc<dyn PhysicalExpr>,
+    accum_fields: Vec<Field>,
+    stats_type: StatsType,
+}
+
+impl AggStddev {
+    pub fn try_new(child: Arc<dyn PhysicalExpr>, stats_type: StatsType) -> Result<Self> {
+        let accum_fields = vec![
+            Field::new("count", DataType::UInt64, false),
+            Field::new("mean", DataType::Float64, false),
+            Field::new("m2", DataType::Float64, false),
+        ];
+        Ok(Self {
+            child,
+            accum_fields,
+            stats_type,
+        })
+    }
+}
+
+impl Debug for AggStddev {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.stats_type {
+            StatsType::Sample => write!(f, "StddevSamp({:?})", self.child),
+            StatsType::Population => write!(f, "StddevPop({:?})", self.child),
+        }
+    }
+}
+
+impl Agg for AggStddev {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn exprs(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.child.clone()]
+    }
+
+    fn data_type(&self) -> &DataType {
+        &DataType::Float64
+    }
+
+    fn nullable(&self) -> bool {
+        true
+    }
+
+    fn accum_fields(&self) -> &[Field] {
+        &self.accum_fields
+    }
+
+    fn create_accum(&self) -> Result<AggAccumRef> {
+        Ok(Box::new(MomentsAccum {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            stats_type: self.stats_type,
+            output_stddev: true,
+        }))
+    }
+}
+
+/// Shared accumulator for [`AggVariance`] and [`AggStddev`]: running
+/// `count`/`mean`/`m2` moments updated with Welford's online algorithm and
+/// merged with Chan's parallel-combine formula.
+pub struct MomentsAccum {
+    pub count: u64,
+    pub mean: f64,
+    pub m2: f64,
+    stats_type: StatsType,
+    output_stddev: bool,
+}
+
+impl MomentsAccum {
+    fn update(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        let delta2 = x - self.mean;
+        self.m2 += delta * delta2;
+    }
+
+    /// Chan et al.'s parallel combination of two (count, mean, m2) triples.
+    fn merge(&mut self, other_count: u64, other_mean: f64, other_m2: f64) {
+        if other_count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            self.count = other_count;
+            self.mean = other_mean;
+            self.m2 = other_m2;
+            return;
+        }
+        let n = self.count + other_count;
+        let delta = other_mean - self.mean;
+        self.mean += delta * other_count as f64 / n as f64;
+        self.m2 += other_m2 + delta * delta * self.count as f64 * other_count as f64 / n as f64;
+        self.count = n;
+    }
+
+    fn value(&self) -> Option<f64> {
+        let variance = match self.stats_type {
+            StatsType::Sample if self.count < 2 => return None,
+            StatsType::Sample => self.m2 / (self.count - 1) as f64,
+            StatsType::Population if self.count == 0 => return None,
+            StatsType::Population => self.m2 / self.count as f64,
+        };
+        Some(if self.output_stddev {
+            variance.sqrt()
+        } else {
+            variance
+        })
+    }
+}
+
+impl AggAccum for MomentsAccum {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+
+    fn mem_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn load(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        let count = downcast_value!(values[0], UInt64Array);
+        let mean = downcast_value!(values[1], Float64Array);
+        let m2 = downcast_value!(values[2], Float64Array);
+        self.count = count.value(row_idx);
+        self.mean = mean.value(row_idx);
+        self.m2 = m2.value(row_idx);
+        Ok(())
+    }
+
+    fn save(&self, builders: &mut [Box<dyn ArrayBuilder>]) -> Result<()> {
+        builders[0]
+            .as_any_mut()
+            .downcast_mut::<UInt64Builder>()
+            .unwrap()
+            .append_value(self.count);
+        builders[1]
+            .as_any_mut()
+            .downcast_mut::<Float64Builder>()
+            .unwrap()
+            .append_value(self.mean);
+        builders[2]
+            .as_any_mut()
+            .downcast_mut::<Float64Builder>()
+            .unwrap()
+            .append_value(self.m2);
+        Ok(())
+    }
+
+    fn save_final(&self, builder: &mut Box<dyn ArrayBuilder>) -> Result<()> {
+        builder
+            .as_any_mut()
+            .downcast_mut::<Float64Builder>()
+            .unwrap()
+            .append_option(self.value());
+        Ok(())
+    }
+
+    fn partial_update(&mut self, values: &[ArrayRef], row_idx: usize) -> Result<()> {
+        if let Some(x) = value_as_f64(&values[0], row_idx)? {
+            self.update(x);
+        }
+        Ok(())
+    }
+
+    fn partial_update_all(&mut self, values: &[ArrayRef]) -> Result<()> {
+        for row_idx in 0..values[0].len() {
+            self.partial_update(values, row_idx)?;
+        }
+        Ok(())
+    }
+
+    fn partial_merge(&mut self, another: AggAccumRef) -> Result<()> {
+        let another_moments = another.into_any().downcast::<MomentsAccum>().unwrap();
+        self.merge(another_moments.count, another_moments.mean, another_moments.m2);
+        Ok(())
+    }
+
+    fn partial_merge_from_array(
+        &mut self,
+        partial_agg_values: &[ArrayRef],
+        row_idx: usize,
+    ) -> Result<()> {
+        let count = downcast_value!(partial_agg_values[0], UInt64Array).value(row_idx);
+        let mean = downcast_value!(partial_agg_values[1], Float64Array).value(row_idx);
+        let m2 = downcast_value!(partial_agg_values[2], Float64Array).value(row_idx);
+        self.merge(count, mean, m2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_accum(stats_type: StatsType, output_stddev: bool) -> MomentsAccum {
+        MomentsAccum {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            stats_type,
+            output_stddev,
+        }
+    }
+
+    #[test]
+    fn test_sample_variance_matches_known_value() {
+        let mut accum = new_accum(StatsType::Sample, false);
+        for x in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            accum.update(x);
+        }
+        // population variance is 4.0 for this dataset; sample variance
+        // scales by n/(n-1) = 8/7.
+        assert!((accum.value().unwrap() - 4.0 * 8.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sample_variance_is_null_below_two_observations() {
+        let mut accum = new_accum(StatsType::Sample, false);
+        assert_eq!(accum.value(), None);
+        accum.update(1.0);
+        assert_eq!(accum.value(), None);
+    }
+
+    #[test]
+    fn test_merge_matches_single_pass() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+
+        let mut whole = new_accum(StatsType::Population, true);
+        for &x in &values {
+            whole.update(x);
+        }
+
+        let mut first_half = new_accum(StatsType::Population, true);
+        let mut second_half = new_accum(StatsType::Population, true);
+        for (i, &x) in values.iter().enumerate() {
+            if i < values.len() / 2 {
+                first_half.update(x);
+            } else {
+                second_half.update(x);
+            }
+        }
+        first_half.merge(second_half.count, second_half.mean, second_half.m2);
+
+        assert_eq!(first_half.count, whole.count);
+        assert!((first_half.value().unwrap() - whole.value().unwrap()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_save_load_roundtrip_preserves_moments() {
+        let mut accum = new_accum(StatsType::Sample, false);
+        for x in [1.0, 2.0, 3.0, 4.0, 5.0] {
+            accum.update(x);
+        }
+
+        let mut builders: Vec<Box<dyn ArrayBuilder>> = vec![
+            Box::new(UInt64Builder::new()),
+            Box::new(Float64Builder::new()),
+            Box::new(Float64Builder::new()),
+        ];
+        accum.save(&mut builders).unwrap();
+        let count_array = builders[0].finish();
+        let mean_array = builders[1].finish();
+        let m2_array = builders[2].finish();
+
+        let mut reloaded = new_accum(StatsType::Sample, false);
+        reloaded
+            .load(&[Arc::new(count_array), Arc::new(mean_array), Arc::new(m2_array)], 0)
+            .unwrap();
+
+        assert_eq!(reloaded.count, accum.count);
+        assert_eq!(reloaded.mean, accum.mean);
+        assert_eq!(reloaded.m2, accum.m2);
+    }
+
+    #[test]
+    fn test_partial_update_dispatches_non_float64_input() {
+        let mut accum = new_accum(StatsType::Population, false);
+        let values: Vec<ArrayRef> = vec![Arc::new(Int32Array::from(vec![2, 4, 4, 4, 5, 5, 7, 9]))];
+        for row_idx in 0..values[0].len() {
+            accum.partial_update(&values, row_idx).unwrap();
+        }
+        assert!((accum.value().unwrap() - 4.0).abs() < 1e-9);
+    }
+}
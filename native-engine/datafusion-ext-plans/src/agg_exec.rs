@@ -0,0 +1,477 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Vectorized hash-based group-by driver on top of the per-group
+//! `Agg`/`AggAccum` primitives in [`crate::agg`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ahash::RandomState;
+use arrow::array::*;
+use arrow::datatypes::{Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow::row::{Row, RowConverter, Rows, SortField};
+use datafusion::common::Result;
+use datafusion::physical_expr::PhysicalExpr;
+
+use crate::agg::{hash_builder, Agg, AggAccum, AggAccumRef};
+
+/// Memory threshold, in bytes, above which [`HashAggMap`] spills its
+/// partial accumulator state instead of continuing to grow unbounded.
+const DEFAULT_SPILL_THRESHOLD: usize = 256 * 1024 * 1024;
+
+/// One group's partial state, ready to be persisted by the caller and
+/// later restored via [`HashAggMap::merge_spilled`].
+pub struct SpilledGroup {
+    /// The grouping columns' values for this group, one single-row array
+    /// per `group_expr`.
+    pub key_values: Vec<ArrayRef>,
+    /// This group's accumulator state, as written by `AggAccum::save`.
+    pub partial_agg_values: Vec<Box<dyn ArrayBuilder>>,
+}
+
+/// Vectorized hash-based group-by: ingests `RecordBatch`es, builds a
+/// composite group key from `group_exprs`, and maintains one
+/// [`AggAccumRef`] per aggregate per group.
+///
+/// Group keys are kept in a single append-only [`Rows`] buffer
+/// (`group_rows`), indexed by a fixed-seed aHash of each row's bytes
+/// (`index: HashMap<u64, Vec<usize>>`). Hash-map lookups and resizing only
+/// ever touch that cached `u64`, never the row bytes themselves — the
+/// bytes are compared (via `Row`'s `Eq`) only against the rows already
+/// stored under the same 64-bit hash, to confirm a match.
+pub struct HashAggMap {
+    group_exprs: Vec<Arc<dyn PhysicalExpr>>,
+    aggs: Vec<Arc<dyn Agg>>,
+    input_schema: SchemaRef,
+    row_converter: RowConverter,
+    row_hasher: RandomState,
+    /// One row per distinct group, in first-seen order; `group_accums[i]`
+    /// holds the accumulators for `group_rows.row(i)`.
+    group_rows: Rows,
+    group_accums: Vec<Vec<AggAccumRef>>,
+    /// Maps a group row's cached hash to the indices (into `group_rows`/
+    /// `group_accums`) of groups that hash to it.
+    index: HashMap<u64, Vec<usize>>,
+    /// Running total of `mem_used()`, kept up to date incrementally on
+    /// group creation/`partial_update`/`merge_spilled` rather than
+    /// resummed from every live group's `mem_size()` on every batch — at
+    /// the group cardinalities spilling exists for, a full rescan per
+    /// batch would itself become the bottleneck.
+    mem_used: usize,
+    spill_threshold: usize,
+    /// Groups evicted by a [`Self::spill`] that [`Self::update_batch`]
+    /// triggered, held here until the caller drains them with [`Self::take_spilled`] and persists them —
+    /// crossing the spill threshold must never simply discard state.
+    spilled: Vec<SpilledGroup>,
+}
+
+impl HashAggMap {
+    pub fn try_new(
+        group_exprs: Vec<Arc<dyn PhysicalExpr>>,
+        aggs: Vec<Arc<dyn Agg>>,
+        input_schema: SchemaRef,
+    ) -> Result<Self> {
+        let sort_fields = group_exprs
+            .iter()
+            .map(|expr| Ok(SortField::new(expr.data_type(&input_schema)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let row_converter = RowConverter::new(sort_fields)?;
+        let group_rows = row_converter.empty_rows(0, 0);
+
+        Ok(Self {
+            group_exprs,
+            aggs,
+            input_schema,
+            row_converter,
+            row_hasher: hash_builder(),
+            group_rows,
+            group_accums: vec![],
+            index: HashMap::new(),
+            mem_used: 0,
+            spill_threshold: DEFAULT_SPILL_THRESHOLD,
+            spilled: vec![],
+        })
+    }
+
+    pub fn with_spill_threshold(mut self, spill_threshold: usize) -> Self {
+        self.spill_threshold = spill_threshold;
+        self
+    }
+
+    /// Total accumulator memory currently tracked, via the incrementally
+    /// maintained `mem_used` field.
+    fn mem_used(&self) -> usize {
+        self.mem_used
+    }
+
+    /// Finds the index of `row` in `group_rows`, inserting a fresh set of
+    /// accumulators (via `Agg::create_accum`) if it hasn't been seen yet.
+    /// The map lookup/insert only ever touches `hash`, a `u64` cached
+    /// alongside the key, not the row bytes.
+    fn group_index_for(&mut self, row: Row<'_>) -> Result<usize> {
+        let hash = self.row_hasher.hash_one(row.as_ref());
+        if let Some(idx) = self
+            .index
+            .get(&hash)
+            .and_then(|candidates| candidates.iter().copied().find(|&idx| self.group_rows.row(idx) == row))
+        {
+            return Ok(idx);
+        }
+
+        let idx = self.group_rows.num_rows();
+        self.group_rows.push(row);
+        let accums = self
+            .aggs
+            .iter()
+            .map(|agg| agg.create_accum())
+            .collect::<Result<Vec<_>>>()?;
+        self.mem_used += accums.iter().map(|accum| accum.mem_size()).sum::<usize>();
+        self.group_accums.push(accums);
+        self.index.entry(hash).or_insert_with(Vec::new).push(idx);
+        Ok(idx)
+    }
+
+    /// Applies `f` to every accumulator of the group at `group_idx`,
+    /// keeping `mem_used` in sync with the resulting size change instead
+    /// of requiring a full rescan.
+    fn update_group_accums(
+        &mut self,
+        group_idx: usize,
+        mut f: impl FnMut(usize, &mut AggAccumRef) -> Result<()>,
+    ) -> Result<()> {
+        let mut mem_delta: i64 = 0;
+        for (agg_idx, accum) in self.group_accums[group_idx].iter_mut().enumerate() {
+            let before = accum.mem_size() as i64;
+            f(agg_idx, accum)?;
+            let after = accum.mem_size() as i64;
+            mem_delta += after - before;
+        }
+        self.mem_used = (self.mem_used as i64 + mem_delta).max(0) as usize;
+        Ok(())
+    }
+
+    /// Feeds one input batch through the hash table, creating new groups
+    /// as needed and driving each group's accumulators with
+    /// `partial_update`. Spills to [`Self::spilled`] whenever live
+    /// accumulator memory crosses `spill_threshold`.
+    pub fn update_batch(&mut self, batch: &RecordBatch) -> Result<()> {
+        let group_values = self
+            .group_exprs
+            .iter()
+            .map(|expr| Ok(expr.evaluate(batch)?.into_array(batch.num_rows())))
+            .collect::<Result<Vec<_>>>()?;
+        let agg_input_values = self
+            .aggs
+            .iter()
+            .map(|agg| {
+                agg.exprs()
+                    .iter()
+                    .map(|expr| Ok(expr.evaluate(batch)?.into_array(batch.num_rows())))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rows = self.row_converter.convert_columns(&group_values)?;
+
+        for row_idx in 0..batch.num_rows() {
+            let group_idx = self.group_index_for(rows.row(row_idx))?;
+            self.update_group_accums(group_idx, |agg_idx, accum| {
+                accum.partial_update(&agg_input_values[agg_idx], row_idx)
+            })?;
+        }
+
+        if self.mem_used() > self.spill_threshold {
+            let spilled = self.spill()?;
+            self.spilled.extend(spilled);
+        }
+        Ok(())
+    }
+
+    /// Spills every live group's partial state through `AggAccum::save`
+    /// and empties the in-memory table. The spilled groups are returned to
+    /// the caller rather than dropped — ownership of the state transfers,
+    /// it is never simply thrown away.
+    pub fn spill(&mut self) -> Result<Vec<SpilledGroup>> {
+        let n = self.group_rows.num_rows();
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let key_columns = self
+            .row_converter
+            .convert_rows((0..n).map(|i| self.group_rows.row(i)))?;
+
+        let mut spilled = Vec::with_capacity(n);
+        for (i, accums) in self.group_accums.drain(..).enumerate() {
+            let key_values = key_columns.iter().map(|col| col.slice(i, 1)).collect();
+            let mut partial_agg_values = Vec::new();
+            for (agg, accum) in self.aggs.iter().zip(accums.iter()) {
+                let mut builders: Vec<Box<dyn ArrayBuilder>> = agg
+                    .accum_fields()
+                    .iter()
+                    .map(|field| arrow::array::make_builder(field.data_type(), 1))
+                    .collect();
+                accum.save(&mut builders)?;
+                partial_agg_values.extend(builders);
+            }
+            spilled.push(SpilledGroup {
+                key_values,
+                partial_agg_values,
+            });
+        }
+        self.group_rows = self.row_converter.empty_rows(0, 0);
+        self.index.clear();
+        self.mem_used = 0;
+        Ok(spilled)
+    }
+
+    /// Drains and returns groups evicted by a [`Self::spill`] that
+    /// [`Self::update_batch`] triggered, so the caller can persist them (e.g. to a spill file) exactly once.
+    pub fn take_spilled(&mut self) -> Vec<SpilledGroup> {
+        std::mem::take(&mut self.spilled)
+    }
+
+    /// Restores a spilled group, merging its saved state back into a
+    /// fresh (or existing) accumulator via `partial_merge_from_array`.
+    pub fn merge_spilled(
+        &mut self,
+        key_values: &[ArrayRef],
+        partial_agg_values: &[Vec<ArrayRef>],
+        row_idx: usize,
+    ) -> Result<()> {
+        let key_rows = self.row_converter.convert_columns(key_values)?;
+        let group_idx = self.group_index_for(key_rows.row(row_idx))?;
+        self.update_group_accums(group_idx, |agg_idx, accum| {
+            accum.partial_merge_from_array(&partial_agg_values[agg_idx], row_idx)
+        })
+    }
+
+    pub fn num_groups(&self) -> usize {
+        self.group_rows.num_rows()
+    }
+
+    /// The schema of the `RecordBatch` produced by [`Self::finish`]:
+    /// grouping columns followed by one column per aggregate.
+    pub fn output_schema(&self) -> Result<SchemaRef> {
+        let mut fields = Vec::with_capacity(self.group_exprs.len() + self.aggs.len());
+        for (i, expr) in self.group_exprs.iter().enumerate() {
+            fields.push(Field::new(
+                format!("group_{i}"),
+                expr.data_type(&self.input_schema)?,
+                expr.nullable(&self.input_schema)?,
+            ));
+        }
+        for agg in &self.aggs {
+            fields.push(Field::new(
+                format!("{:?}", agg),
+                agg.data_type().clone(),
+                agg.nullable(),
+            ));
+        }
+        Ok(Arc::new(Schema::new(fields)))
+    }
+
+    /// Reconstructs the grouping columns (via `RowConverter::convert_rows`)
+    /// and calls `AggAccum::save_final` for every live group, producing the
+    /// fully aggregated output batch. Consumes all live (non-spilled)
+    /// state; spilled groups must be restored via [`Self::merge_spilled`]
+    /// before calling this.
+    pub fn finish(&mut self) -> Result<RecordBatch> {
+        let n = self.group_rows.num_rows();
+        let mut key_columns = self
+            .row_converter
+            .convert_rows((0..n).map(|i| self.group_rows.row(i)))?;
+
+        let mut value_builders: Vec<Box<dyn ArrayBuilder>> = self
+            .aggs
+            .iter()
+            .map(|agg| arrow::array::make_builder(agg.data_type(), n))
+            .collect();
+        for accums in &self.group_accums {
+            for (agg_idx, accum) in accums.iter().enumerate() {
+                accum.save_final(&mut value_builders[agg_idx])?;
+            }
+        }
+
+        let mut columns = Vec::with_capacity(key_columns.len() + value_builders.len());
+        columns.append(&mut key_columns);
+        columns.extend(value_builders.into_iter().map(|mut b| b.finish()));
+
+        self.group_rows = self.row_converter.empty_rows(0, 0);
+        self.group_accums.clear();
+        self.index.clear();
+        self.mem_used = 0;
+
+        Ok(RecordBatch::try_new(self.output_schema()?, columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agg::max::AggMax;
+    use arrow::datatypes::DataType;
+    use datafusion::physical_expr::expressions::Column;
+
+    fn test_batch() -> (SchemaRef, RecordBatch) {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 1, 1, 1])),
+                Arc::new(Int64Array::from(vec![3, 9, 4, 1])),
+            ],
+        )
+        .unwrap();
+        (schema, batch)
+    }
+
+    fn test_map(schema: SchemaRef) -> HashAggMap {
+        let group_exprs: Vec<Arc<dyn PhysicalExpr>> = vec![Arc::new(Column::new("k", 0))];
+        let aggs: Vec<Arc<dyn Agg>> = vec![Arc::new(
+            AggMax::try_new(Arc::new(Column::new("v", 1)), DataType::Int64).unwrap(),
+        )];
+        HashAggMap::try_new(group_exprs, aggs, schema).unwrap()
+    }
+
+    #[test]
+    fn test_update_batch_creates_one_group_per_distinct_key() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 1, 2])),
+                Arc::new(Int64Array::from(vec![3, 9, 4])),
+            ],
+        )
+        .unwrap();
+
+        let mut map = test_map(schema);
+        map.update_batch(&batch).unwrap();
+        assert_eq!(map.num_groups(), 2);
+    }
+
+    #[test]
+    fn test_finish_produces_aggregated_batch() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 1, 2, 2, 2])),
+                Arc::new(Int64Array::from(vec![3, 9, 4, 1, 7])),
+            ],
+        )
+        .unwrap();
+
+        let mut map = test_map(schema);
+        map.update_batch(&batch).unwrap();
+        let output = map.finish().unwrap();
+        assert_eq!(output.num_rows(), 2);
+
+        let keys = output
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        let maxes = output
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        let mut by_key: HashMap<i32, i64> = HashMap::new();
+        for i in 0..output.num_rows() {
+            by_key.insert(keys.value(i), maxes.value(i));
+        }
+        assert_eq!(by_key.get(&1), Some(&9));
+        assert_eq!(by_key.get(&2), Some(&7));
+        assert_eq!(map.num_groups(), 0, "finish() should drain live state");
+    }
+
+    #[test]
+    fn test_spill_then_merge_preserves_group_state() {
+        let (schema, batch) = test_batch();
+        let mut map = test_map(schema);
+        map.update_batch(&batch).unwrap();
+        assert_eq!(map.num_groups(), 1);
+
+        // crossing the spill threshold must not discard accumulator state:
+        // every live group comes back out of spill().
+        let spilled = map.spill().unwrap();
+        assert_eq!(spilled.len(), 1);
+        assert_eq!(map.num_groups(), 0);
+
+        for group in spilled {
+            let partial_agg_values: Vec<Vec<ArrayRef>> = group
+                .partial_agg_values
+                .into_iter()
+                .map(|mut builder| vec![builder.finish()])
+                .collect();
+            map.merge_spilled(&group.key_values, &partial_agg_values, 0)
+                .unwrap();
+        }
+        assert_eq!(map.num_groups(), 1);
+
+        let output = map.finish().unwrap();
+        let maxes = output
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(maxes.value(0), 9);
+    }
+
+    #[test]
+    fn test_mem_used_tracked_incrementally_without_rescan() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3])),
+                Arc::new(Int64Array::from(vec![3, 9, 4])),
+            ],
+        )
+        .unwrap();
+
+        let mut map = test_map(schema);
+        map.update_batch(&batch).unwrap();
+
+        let rescanned: usize = map
+            .group_accums
+            .iter()
+            .flatten()
+            .map(|accum| accum.mem_size())
+            .sum();
+        assert_eq!(map.mem_used(), rescanned);
+        assert!(map.mem_used() > 0);
+
+        // spill/finish hand every live group's memory off to the caller,
+        // so the running counter must drop back to zero rather than
+        // keep counting memory that's no longer tracked here.
+        map.spill().unwrap();
+        assert_eq!(map.mem_used(), 0);
+    }
+}